@@ -16,6 +16,32 @@ use std::path::PathBuf;
 
 use filetime::FileTime;
 
+use sha2::{Digest, Sha256};
+
+use serde::{Deserialize, Serialize};
+
+// Bump whenever `CachedOutput`'s shape changes so old entries get discarded
+// instead of misread.
+const CACHE_FORMAT_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct BinaryMetadata {
+    mtime: i64,
+    size: u64,
+}
+
+// Everything a single cache entry needs, serialized to one file so a crash
+// mid-write can never leave e.g. an exit code without its matching stdout.
+#[derive(Serialize, Deserialize, Debug)]
+struct CachedOutput {
+    version: u8,
+    status: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    created_at: i64,
+    binary_metadata: Option<BinaryMetadata>,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -26,10 +52,25 @@ struct Args {
     #[arg(short, long, default_value_t = ("1min".to_string()))]
     cache_duration: String,
 
+    // Serve cached output immediately while refreshing it in the background,
+    // as long as the entry is younger than this duration. Must be >= cache_duration.
+    #[arg(long)]
+    stale: Option<String>,
+
     // Should we cache results from failed commands
     #[arg(long, default_value_t = false)]
     cache_failures: bool,
 
+    // Environment variable (repeatable) that is significant to the cache key,
+    // e.g. `--env TZ` so `TZ=UTC date` and `TZ=PST date` get separate entries.
+    #[arg(long = "env")]
+    env: Vec<String>,
+
+    // Make the current working directory significant to the cache key, and
+    // run the cached command in that same directory.
+    #[arg(long)]
+    use_cwd: bool,
+
     // Verbose mode
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
@@ -42,11 +83,24 @@ struct Args {
     #[arg(long, default_value_t = false)]
     clear_all: bool,
 
+    // Run the command and populate the cache, suppressing its output. Useful
+    // for pre-seeding the cache, and is how `--stale` refreshes in the background.
+    #[arg(long, default_value_t = false)]
+    warm: bool,
+
+    // List cached entries for this command with their age and exit code
+    #[arg(long, default_value_t = false)]
+    list: bool,
+
     // The command to execute
     command: String,
     command_args: Vec<String>,
 }
 
+fn lock_options() -> FileOptions {
+    return FileOptions::new().write(true).create(true).append(false);
+}
+
 fn get_cache_file_with_prefix(
     prefix: &str,
     path: &PathBuf,
@@ -62,133 +116,365 @@ fn get_cache_file_with_prefix(
     return dirs.get_cache_file(out_path);
 }
 
-fn encode_command_args(command_args: &Vec<String>) -> String {
-    let joined_args = command_args.join("\n");
-    let encoded_args = base64::encode(joined_args);
-    return encoded_args;
+// Values of the `--env` vars that were asked to be cache-significant, in the
+// order they were given, as "NAME=value" pairs.
+fn cache_key_env_pairs(env_vars: &Vec<String>) -> Vec<String> {
+    return env_vars
+        .iter()
+        .map(|name| format!("{}={}", name, std::env::var(name).unwrap_or_default()))
+        .collect();
 }
 
-fn get_cached_paths(
-    dirs: &BaseDirectories,
-    path: &PathBuf,
-    encoded_args: &str,
-) -> (PathBuf, PathBuf, PathBuf, PathBuf) {
-    let lockfile_path = get_cache_file_with_prefix("lockfile_".into(), &path, &encoded_args, dirs);
-    let exitcode_path = get_cache_file_with_prefix("exitcode_".into(), &path, &encoded_args, dirs);
-    let stdout_path = get_cache_file_with_prefix("stdout_".into(), &path, &encoded_args, dirs);
-    let stderr_path = get_cache_file_with_prefix("stderr_".into(), &path, &encoded_args, dirs);
-    return (lockfile_path, exitcode_path, stdout_path, stderr_path);
+fn hex_encode(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(out, "{:02x}", byte);
+    }
+    return out;
+}
+
+// A short, filesystem-safe, human-readable fragment of `s`, for appending to
+// a hashed filename so `--verbose` output stays recognizable. Not part of
+// the cache key itself.
+fn sanitize_suffix(s: &str) -> String {
+    let cleaned: String = s
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    return cleaned.chars().take(40).collect();
+}
+
+// Hash one named, length-prefixed section into `hasher`: a tag (so sections
+// can never alias each other), an item count, then each item prefixed with
+// its own byte length. This makes section and item boundaries unambiguous,
+// so e.g. an `args` section containing `"FOO=bar"` can never hash the same
+// as an `env` section containing `"FOO=bar"`.
+fn hash_section(hasher: &mut Sha256, tag: &[u8], items: &[String]) {
+    hasher.update(tag);
+    hasher.update(&[0u8]);
+    hasher.update(&(items.len() as u64).to_le_bytes());
+    for item in items {
+        hasher.update(&(item.len() as u64).to_le_bytes());
+        hasher.update(item.as_bytes());
+    }
+}
+
+// Hash the canonical key material (args, and any cache-significant env/cwd;
+// the command itself keys the directory) into a fixed-length,
+// collision-resistant digest, instead of base64-encoding it directly into the
+// filename. Length-prefixed fields mean `["a\nb"]` and `["a", "b"]` no longer
+// hash the same way, and arbitrary argument bytes no longer risk the 255-byte
+// filename limit.
+fn encode_cache_key(
+    command_args: &Vec<String>,
+    env_pairs: &Vec<String>,
+    cwd: &Option<PathBuf>,
+) -> String {
+    let mut hasher = Sha256::new();
+    hash_section(&mut hasher, b"args", command_args);
+    hash_section(&mut hasher, b"env", env_pairs);
+    let cwd_items: Vec<String> = cwd
+        .iter()
+        .map(|cwd| cwd.to_string_lossy().into_owned())
+        .collect();
+    hash_section(&mut hasher, b"cwd", &cwd_items);
+
+    let digest = hex_encode(&hasher.finalize());
+
+    let suffix = sanitize_suffix(&command_args.join("_"));
+    if suffix.is_empty() {
+        return digest;
+    }
+    return format!("{}_{}", digest, suffix);
+}
+
+// Resolve `command` to an absolute path the same way the shell would: if it
+// already contains a path separator, use it as-is, otherwise search $PATH.
+fn resolve_binary_path(command: &str) -> Option<PathBuf> {
+    if command.contains(std::path::MAIN_SEPARATOR) {
+        return fs::canonicalize(command).ok();
+    }
+
+    let path_var = std::env::var_os("PATH")?;
+    for dir in std::env::split_paths(&path_var) {
+        let candidate = dir.join(command);
+        if candidate.is_file() {
+            return fs::canonicalize(candidate).ok();
+        }
+    }
+    return None;
+}
+
+// A snapshot of the resolved binary's mtime and size, used to detect that
+// the cached tool itself changed (upgrade, asdf/nvm version switch, ...).
+fn binary_metadata(command: &str) -> Option<BinaryMetadata> {
+    let binary_path = resolve_binary_path(command)?;
+    let meta = fs::metadata(binary_path).ok()?;
+    let mtime = FileTime::from_last_modification_time(&meta);
+    return Some(BinaryMetadata {
+        mtime: mtime.seconds(),
+        size: meta.len(),
+    });
+}
+
+fn get_entry_path(dirs: &BaseDirectories, path: &PathBuf, encoded_args: &str) -> PathBuf {
+    return get_cache_file_with_prefix("entry_".into(), &path, &encoded_args, dirs);
+}
+
+// One lock file covering the whole command directory. Every operation that
+// mutates entries under this directory — a normal miss, `--clear`, `--warm`,
+// `--clear-all` — and `--list` take this same lock, so management operations
+// and writers can never interleave. The normal hit path reads its entry
+// without the lock, since entries are replaced atomically.
+fn get_dir_lock_path(dirs: &BaseDirectories, path: &PathBuf) -> PathBuf {
+    return get_cache_file_with_prefix("lockfile_".into(), &path, "all", dirs);
+}
+
+// Remove just the entry matching this command+args key.
+fn clear_entry(entry_path: &PathBuf) -> io::Result<()> {
+    match fs::remove_file(entry_path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Remove every cached entry under this command's base directory, leaving the
+// directory-wide lock file in place.
+fn clear_all_entries(path: &PathBuf, dir_lock_path: &PathBuf) -> io::Result<()> {
+    for dir_entry in fs::read_dir(path)? {
+        let dir_entry = dir_entry?;
+        let file_path = dir_entry.path();
+        if &file_path == dir_lock_path {
+            continue;
+        }
+        let name = dir_entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("entry_") {
+            fs::remove_file(file_path)?;
+        }
+    }
+    return Ok(());
+}
+
+// All cached entries under this command's base directory, paired with their
+// age in seconds, for `--list`.
+fn list_entries(path: &PathBuf, dir_lock_path: &PathBuf) -> io::Result<Vec<(String, CachedOutput, i64)>> {
+    let now = FileTime::now().seconds();
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(path)? {
+        let dir_entry = dir_entry?;
+        let file_path = dir_entry.path();
+        if &file_path == dir_lock_path {
+            continue;
+        }
+        let name = dir_entry.file_name();
+        let name = name.to_string_lossy().into_owned();
+        if !name.starts_with("entry_") {
+            continue;
+        }
+        if let Some(entry) = load_cache_entry(&file_path) {
+            let age = now - entry.created_at;
+            entries.push((name, entry, age));
+        }
+    }
+    return Ok(entries);
 }
 
-fn get_cached_value<'a>(
-    paths: &'a (PathBuf, PathBuf, PathBuf, PathBuf),
+// Re-invoke ourselves with `--warm` so the command re-runs and overwrites the
+// cache entry in a detached child, letting the foreground return immediately
+// with the stale value it already has.
+fn spawn_background_refresh(args: &Args) {
+    let current_exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(_) => return,
+    };
+
+    let mut child = Command::new(current_exe);
+    child.arg("--warm");
+    child.arg("--cache-duration").arg(&args.cache_duration);
+    if let Some(stale) = &args.stale {
+        child.arg("--stale").arg(stale);
+    }
+    if let Some(cache_seconds) = args.cache_seconds {
+        child.arg("--cache-seconds").arg(cache_seconds.to_string());
+    }
+    if args.cache_failures {
+        child.arg("--cache-failures");
+    }
+    for name in &args.env {
+        child.arg("--env").arg(name);
+    }
+    if args.use_cwd {
+        child.arg("--use-cwd");
+    }
+    child.arg(&args.command);
+    child.args(&args.command_args);
+
+    child.stdin(std::process::Stdio::null());
+    child.stdout(std::process::Stdio::null());
+    child.stderr(std::process::Stdio::null());
+
+    let _ = child.spawn();
+}
+
+// Read and deserialize the entry file, discarding it on any format mismatch
+// (corrupt write, or an older/newer `CACHE_FORMAT_VERSION`).
+fn load_cache_entry(entry_path: &PathBuf) -> Option<CachedOutput> {
+    let bytes = fs::read(entry_path).ok()?;
+    let entry: CachedOutput = serde_json::from_slice(&bytes).ok()?;
+    if entry.version != CACHE_FORMAT_VERSION {
+        return None;
+    }
+    return Some(entry);
+}
+
+fn get_cached_value(
+    entry_path: &PathBuf,
+    command: &str,
     duration_secs: u64,
     cache_failures: bool,
-) -> Option<(i32, PathBuf, PathBuf)> {
-    let meta = fs::metadata(&paths.1).ok()?;
-    let ftime = FileTime::from_last_modification_time(&meta);
-    let now_time = FileTime::now();
-
-    let time_since_modification = now_time.seconds() - ftime.seconds();
-    if time_since_modification >= duration_secs.try_into().unwrap() {
-        // dbg!(time_since_modification);
+) -> Option<CachedOutput> {
+    let entry = load_cache_entry(entry_path)?;
+
+    let now = FileTime::now().seconds();
+    if now - entry.created_at >= duration_secs.try_into().unwrap() {
         return None;
     }
 
-    let exitcode = match fs::read_to_string(&paths.1) {
-        Ok(exitcode_str) => match exitcode_str.parse::<i32>() {
-            Ok(exitcode) => exitcode,
-            Err(_) => return None,
-        },
-        Err(_) => return None,
-    };
+    // If we have a record of the binary's mtime/size, and the binary has
+    // since changed (upgrade, asdf/nvm switch, ...), treat this as a miss
+    // even though the TTL hasn't expired yet.
+    if let Some(stored) = &entry.binary_metadata {
+        if let Some(current) = binary_metadata(command) {
+            if *stored != current {
+                return None;
+            }
+        }
+    }
 
-    if exitcode != 0 && !cache_failures {
-        // Command had non-zero exit code, and we don't cache that so ignore it
-        // dbg!("Command failed: {}", time_since_modification);
-        return None;
+    if let Some(exitcode) = entry.status {
+        if exitcode != 0 && !cache_failures {
+            // Command had non-zero exit code, and we don't cache that so ignore it
+            return None;
+        }
     }
 
-    return Some((exitcode, paths.2.to_path_buf(), paths.3.to_path_buf()));
+    return Some(entry);
+}
+
+// Serialize `entry` to a temp file next to `entry_path` and `rename` it into
+// place, so a crash mid-write can never leave a partially written entry.
+fn write_cache_entry(entry_path: &PathBuf, entry: &CachedOutput) -> Option<()> {
+    let mut tmp_name = entry_path.clone().into_os_string();
+    tmp_name.push(format!(".tmp.{}", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let bytes = serde_json::to_vec(entry).ok()?;
+    fs::write(&tmp_path, &bytes).ok()?;
+    fs::rename(&tmp_path, entry_path).ok()?;
+    return Some(());
 }
 
 fn run_and_put_cached_value(
     dirs: &BaseDirectories,
     args: &Args,
-    paths: &(PathBuf, PathBuf, PathBuf, PathBuf),
-) -> Option<(Option<i32>, PathBuf, PathBuf)> {
-    let command_result = Command::new(args.command.clone())
-        .args(args.command_args.clone())
-        .output();
+    path: &PathBuf,
+    entry_path: &PathBuf,
+) -> Option<CachedOutput> {
+    let mut command = Command::new(args.command.clone());
+    command.args(args.command_args.clone());
+
+    if args.use_cwd {
+        command.current_dir(
+            std::env::current_dir().expect("unable to determine current working directory"),
+        );
+    }
+    // Run with only the cache-significant env vars set, so the invocation
+    // that actually fills the cache matches what was folded into the key.
+    // PATH is kept regardless so bare command names can still be resolved;
+    // it isn't part of the cache key.
+    if !args.env.is_empty() {
+        command.env_clear();
+        if let Ok(path_var) = std::env::var("PATH") {
+            command.env("PATH", path_var);
+        }
+        for name in &args.env {
+            if let Ok(value) = std::env::var(name) {
+                command.env(name, value);
+            }
+        }
+    }
+
+    let command_result = command.output();
 
     let result = match command_result {
         Ok(result) => result,
         Err(_) => return None,
     };
 
-    let exit_code_path = match dirs.place_cache_file(&paths.1) {
-        Ok(path) => path,
-        Err(_) => return None,
-    };
-    let stdout_path = match dirs.place_cache_file(&paths.2) {
-        Ok(path) => path,
-        Err(_) => return None,
-    };
-    let stderr_path = match dirs.place_cache_file(&paths.3) {
-        Ok(path) => path,
-        Err(_) => return None,
-    };
-
-    if let Some(exitcode) = result.status.code() {
-        if fs::write(exit_code_path, exitcode.to_string()).is_err() {
-            return None;
-        };
-    } else {
+    let status = result.status.code();
+    if status.is_none() {
         return None;
     }
 
-    if fs::write(&stdout_path, &result.stdout).is_err() {
-        return None;
+    let entry = CachedOutput {
+        version: CACHE_FORMAT_VERSION,
+        status,
+        stdout: result.stdout,
+        stderr: result.stderr,
+        created_at: FileTime::now().seconds(),
+        binary_metadata: binary_metadata(&args.command),
     };
-    if fs::write(&stderr_path, &result.stderr).is_err() {
-        return None;
+
+    let entry_path = match dirs.place_cache_file(entry_path) {
+        Ok(path) => path,
+        Err(_) => return None,
     };
 
-    return Some((result.status.code(), stdout_path, stderr_path));
+    // Only the write itself needs the lock: entries are written atomically
+    // (temp file + rename), so nothing here must be held across the command
+    // run or the read/hit path, which would otherwise block unrelated fast
+    // hits behind a slow miss or background refresh.
+    let dir_lock_path = get_dir_lock_path(dirs, path);
+    let _lock = FileLock::lock(&dir_lock_path, true, lock_options()).ok()?;
+    write_cache_entry(&entry_path, &entry)?;
+
+    return Some(entry);
 }
 
-fn display_cached_values(stdout: PathBuf, stderr: PathBuf) -> Result<(), io::Error> {
-    let stdout_content = fs::read(stdout)?;
-    io::stdout().write_all(stdout_content.as_slice())?;
-    let stderr_content = fs::read(stderr)?;
-    io::stderr().write_all(stderr_content.as_slice())?;
+fn display_cached_values(entry: &CachedOutput) -> Result<(), io::Error> {
+    io::stdout().write_all(&entry.stdout)?;
+    io::stderr().write_all(&entry.stderr)?;
     return Ok(());
 }
 
 fn main() {
     let args = Args::parse();
 
-    let command_base64 = base64::encode(args.command.clone());
+    let command_key = {
+        let mut hasher = Sha256::new();
+        hasher.update(args.command.as_bytes());
+        let digest = hex_encode(&hasher.finalize());
+        format!("{}_{}", digest, sanitize_suffix(&args.command))
+    };
 
     let dirs = xdg::BaseDirectories::with_prefix("cmdcache").expect("unable to get xdg dirs");
     let path = dirs
-        .create_cache_directory(command_base64)
+        .create_cache_directory(command_key)
         .expect("unable to create cache directory");
     if args.verbose {
         eprintln!("cache_path: {:?}", &path);
     }
 
-    let encoded_args = encode_command_args(&args.command_args);
-
-    if args.clear_all {
-        // remove all cache files for this command
-        todo!()
-    } else if args.clear {
-        // remove all cache files for this command
-        todo!()
-    }
-
-    let paths = get_cached_paths(&dirs, &path, &encoded_args);
+    let cwd = if args.use_cwd {
+        Some(std::env::current_dir().expect("unable to determine current working directory"))
+    } else {
+        None
+    };
+    let env_pairs = cache_key_env_pairs(&args.env);
+    let encoded_args = encode_cache_key(&args.command_args, &env_pairs, &cwd);
 
     let cache_duration_secs = match (args.cache_seconds, &args.cache_duration) {
         (None, cache_duration) => parse_duration(&cache_duration)
@@ -197,28 +483,100 @@ fn main() {
         (Some(duration_seconds), _) => duration_seconds,
     };
 
-    // get a lock on the lock_file
+    let stale_secs = args.stale.as_ref().map(|stale| {
+        let stale_secs = parse_duration(stale)
+            .expect("duration should be valid number of seconds")
+            .as_secs();
+        if stale_secs < cache_duration_secs {
+            panic!(
+                "--stale ({stale}) must be >= --cache-duration ({})",
+                args.cache_duration
+            );
+        }
+        return stale_secs;
+    });
+
+    // Entries are written atomically (temp file + rename), so reads never
+    // need the lock. `--clear-all` and `--list` touch every entry under this
+    // directory at once, so they take the directory-wide lock to stay
+    // consistent with writers; the normal read/hit path below does not.
     let should_we_block = true;
-    let options = FileOptions::new().write(true).create(true).append(false);
+    let dir_lock_path = get_dir_lock_path(&dirs, &path);
+
+    if args.clear_all {
+        let _lock = FileLock::lock(&dir_lock_path, should_we_block, lock_options())
+            .expect("unable to get file lock");
+        clear_all_entries(&path, &dir_lock_path).expect("unable to clear cache entries");
+        return;
+    } else if args.list {
+        let _lock = FileLock::lock(&dir_lock_path, should_we_block, lock_options())
+            .expect("unable to get file lock");
+        let mut entries = list_entries(&path, &dir_lock_path).expect("unable to list cache entries");
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, entry, age) in &entries {
+            println!(
+                "{}\tage={}s\texit={}",
+                name,
+                age,
+                entry.status.map_or("?".to_string(), |code| code.to_string())
+            );
+        }
+        return;
+    }
 
-    let _lock =
-        FileLock::lock(&paths.0, should_we_block, options).expect("unable to get file lock");
+    let entry_path = get_entry_path(&dirs, &path, &encoded_args);
 
-    if let Some((exit_code, stdout, stderr)) =
-        get_cached_value(&paths, cache_duration_secs, args.cache_failures)
-    {
+    if args.clear {
+        let _lock = FileLock::lock(&dir_lock_path, should_we_block, lock_options())
+            .expect("unable to get file lock");
+        clear_entry(&entry_path).expect("unable to clear cache entry");
+        return;
+    }
+
+    // `--warm` is how we re-invoke ourselves in the background to refresh a
+    // stale entry: just run the command and overwrite the cache, silently.
+    if args.warm {
+        run_and_put_cached_value(&dirs, &args, &path, &entry_path);
+        return;
+    }
+
+    if let Some(entry) = get_cached_value(
+        &entry_path,
+        &args.command,
+        cache_duration_secs,
+        args.cache_failures,
+    ) {
         if args.verbose {
-            eprintln!("using cached value: {:?}", (exit_code, &stdout, &stderr));
+            eprintln!("using cached value: {:?}", entry);
         }
-        display_cached_values(stdout, stderr).expect("unable to read and write from cache");
+        let exit_code = entry.status.unwrap_or(1);
+        display_cached_values(&entry).expect("unable to read and write from cache");
         std::process::exit(exit_code);
     }
+
+    if let Some(stale_secs) = stale_secs {
+        if let Some(entry) =
+            get_cached_value(&entry_path, &args.command, stale_secs, args.cache_failures)
+        {
+            if args.verbose {
+                eprintln!(
+                    "using stale cached value, refreshing in background: {:?}",
+                    entry
+                );
+            }
+            let exit_code = entry.status.unwrap_or(1);
+            display_cached_values(&entry).expect("unable to read and write from cache");
+            spawn_background_refresh(&args);
+            std::process::exit(exit_code);
+        }
+    }
+
     if args.verbose {
         eprintln!("== Running...");
     }
 
-    if let Some((exit_code, stdout, stderr)) = run_and_put_cached_value(&dirs, &args, &paths) {
-        display_cached_values(stdout, stderr).expect("unable to read and write from cache");
-        std::process::exit(exit_code.unwrap_or(1));
+    if let Some(entry) = run_and_put_cached_value(&dirs, &args, &path, &entry_path) {
+        display_cached_values(&entry).expect("unable to read and write from cache");
+        std::process::exit(entry.status.unwrap_or(1));
     }
 }